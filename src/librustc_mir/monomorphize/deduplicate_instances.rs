@@ -1,9 +1,14 @@
+use std::mem;
+
 use rustc_data_structures::indexed_vec::IndexVec;
+use rustc::hir::def_id::DefId;
 use rustc::ty::{self, TyCtxt, Ty, ParamTy, TypeFoldable, Instance, ParamEnv};
 use rustc::ty::fold::TypeFolder;
+use rustc::ty::query::Providers;
 use rustc::ty::subst::{Kind, UnpackedKind};
-use rustc::ty::layout::{LayoutCx, LayoutOf};
-use rustc::mir::{Mir, Rvalue, Location};
+use rustc::mir::interpret::{ConstValue, Scalar};
+use rustc::ty::layout::{LayoutCx, LayoutOf, Size};
+use rustc::mir::{Mir, Rvalue, CastKind, Location};
 use rustc::mir::visit::{Visitor, TyContext};
 
 /// Replace substs which aren't used by the function with TyError,
@@ -30,19 +35,31 @@ pub(crate) fn collapse_interchangable_instances<'a, 'tcx>(
     if instance.substs.is_noop() || !tcx.is_mir_available(instance.def_id()) {
         return instance;
     }
+    // `used_substs` is keyed on the `DefId` and analyses `optimized_mir`, so it
+    // only describes the item body. Shims (drop glue, fn-ptr/closure shims, ...)
+    // codegen a synthesised body against the same `DefId` and must not be
+    // collapsed using that analysis.
+    if let ty::InstanceDef::Item(_) = instance.def {} else {
+        return instance;
+    }
     match instance.ty(tcx).sty {
         ty::FnDef(def_id, _) => {
             if tcx.lang_items().items().iter().find(|l|**l == Some(def_id)).is_some() {
                 return instance; // Lang items dont work otherwise
             }
         }
-        _ => return instance, // Closures dont work otherwise
+        // Closures and generators are handled too: the synthetic components of
+        // their substs (closure-kind, signature, upvar types) are marked `Used`
+        // by `used_substs` through the closure type of the body's self argument,
+        // so only a phantom type param threaded through the body can be collapsed.
+        ty::Closure(..) | ty::Generator(..) => {}
+        _ => return instance,
     }
 
-    let used_substs = used_substs_for_instance(tcx, instance);
+    let used_substs = tcx.used_substs(instance.def_id());
     instance.substs = tcx.intern_substs(&instance.substs.into_iter().enumerate().map(|(i, subst)| {
         if let UnpackedKind::Type(ty) = subst.unpack() {
-            let ty = match used_substs.parameters[ParamIdx(i as u32)] {
+            let ty = match param_usage(&used_substs, i) {
                 ParamUsage::Unused => {
                     if false /*param.name.as_str().starts_with("<")*/ {
                         ty.into()
@@ -79,18 +96,53 @@ pub(crate) fn collapse_interchangable_instances<'a, 'tcx>(
                     }
                 }
                 ParamUsage::LayoutUsed => {
+                    // Codegen for this param only depends on its ABI layout, not on
+                    // its concrete identity. Canonicalise it to a `LayoutOnlyParam`
+                    // carrying just `(size, align)`, so that two instances whose
+                    // differing params share a layout collapse to a single one.
                     let layout_cx = LayoutCx {
                         tcx,
                         param_env: ParamEnv::reveal_all(),
                     };
-                    let layout = layout_cx.layout_of(ty).unwrap();
-                    // FIXME: wrong wrong wrong
-                    tcx.mk_ty(ty::LayoutOnlyParam(layout.size, layout.align.abi))
+                    // A type with drop glue is never purely layout-only: the
+                    // concrete destructor has to be called, so keep it intact.
+                    if ty.needs_drop(tcx, ParamEnv::reveal_all()) {
+                        ty.into()
+                    } else {
+                        match layout_cx.layout_of(ty) {
+                            Ok(layout) => {
+                                tcx.mk_ty(ty::LayoutOnlyParam(layout.size, layout.align.abi))
+                            }
+                            // If we can't compute a layout (e.g. an unsized or
+                            // still-erroneous type) we can't canonicalize it, so
+                            // keep the original and let the instances stay distinct.
+                            Err(_) => ty.into(),
+                        }
+                    }
                 }
                 ParamUsage::Used => ty.into(),
             };
             Kind::from(ty)
+        } else if let UnpackedKind::Const(ct) = subst.unpack() {
+            // A const param whose value never reaches codegen can be replaced
+            // by a canonical dummy so the instances collapse; otherwise it is
+            // load-bearing and must be preserved verbatim.
+            match param_usage(&used_substs, i) {
+                ParamUsage::Unused => Kind::from(dummy_const(tcx, ct.ty)),
+                _ => (*subst).clone(),
+            }
         } else {
+            // Lifetimes are erased before monomorphization; assert that so the
+            // structural equality used for deduplication is total across kinds.
+            if let UnpackedKind::Lifetime(region) = subst.unpack() {
+                debug_assert_eq!(
+                    *region,
+                    ty::ReErased,
+                    "unerased region {:?} in substs of {:?}",
+                    region,
+                    instance,
+                );
+            }
             (*subst).clone()
         }
     }).collect::<Vec<_>>());
@@ -98,6 +150,13 @@ pub(crate) fn collapse_interchangable_instances<'a, 'tcx>(
     instance
 }
 
+/// Look up the usage of subst position `i`. The cached `ParamsUsage` is sized
+/// by the item's generic-param count; any subst positions beyond that are
+/// conservatively treated as `Used`.
+fn param_usage(used_substs: &ParamsUsage, i: usize) -> ParamUsage {
+    used_substs.parameters.get(ParamIdx(i as u32)).cloned().unwrap_or(ParamUsage::Used)
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
 struct ParamIdx(u32);
 
@@ -112,11 +171,20 @@ impl ::rustc_data_structures::indexed_vec::Idx for ParamIdx {
     }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+/// How codegen for a generic body depends on a given type parameter.
+///
+/// The variants are ordered by "strength": when a param is observed in more
+/// than one position we keep the `max`, so a single structural use upgrades an
+/// otherwise layout-only param all the way to `Used`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
 enum ParamUsage {
+    /// Never referenced by codegen; can be replaced by a dummy.
     Unused = 0,
-    #[allow(dead_code)]
+    /// Only its size/align reach codegen (stack slots, field offsets, casts);
+    /// can be replaced by a canonical `LayoutOnlyParam`.
     LayoutUsed = 1,
+    /// Structurally significant (trait selection, projections, bare substs);
+    /// must be preserved.
     Used = 2,
 }
 
@@ -137,11 +205,59 @@ impl ParamsUsage {
     }
 }
 
-struct SubstsVisitor<'a, 'gcx: 'a + 'tcx, 'tcx: 'a>(
-    TyCtxt<'a, 'gcx, 'tcx>,
-    &'tcx Mir<'tcx>,
-    ParamsUsage,
-);
+struct SubstsVisitor<'a, 'gcx: 'a + 'tcx, 'tcx: 'a> {
+    tcx: TyCtxt<'a, 'gcx, 'tcx>,
+    usage: ParamsUsage,
+    /// Whether the type currently being folded was reached through a position
+    /// where codegen only depends on its ABI layout. Params observed while this
+    /// is set land at `LayoutUsed`; otherwise they land at `Used`.
+    layout_only: bool,
+}
+
+impl<'a, 'gcx: 'a + 'tcx, 'tcx: 'a> SubstsVisitor<'a, 'gcx, 'tcx> {
+    fn new(
+        tcx: TyCtxt<'a, 'gcx, 'tcx>,
+        len: usize,
+    ) -> SubstsVisitor<'a, 'gcx, 'tcx> {
+        SubstsVisitor {
+            tcx,
+            usage: ParamsUsage::new(len),
+            layout_only: false,
+        }
+    }
+
+    /// Record a usage for `idx`, keeping the strongest reason seen so far.
+    fn mark(&mut self, idx: ParamIdx, usage: ParamUsage) {
+        let slot = &mut self.usage.parameters[idx];
+        *slot = (*slot).max(usage);
+    }
+
+    /// Mark the const param named by `constant`, if any, as `Used`: a const
+    /// reaching this point is consumed by codegen (array length, match
+    /// discriminant, arithmetic), so its value cannot be canonicalized away.
+    fn fold_const(&mut self, constant: &ty::Const<'tcx>) {
+        match constant.val {
+            ConstValue::Param(param) => {
+                self.mark(ParamIdx(param.index), ParamUsage::Used);
+            }
+            // An unevaluated const (e.g. `[T; N + 1]`) can still mention a param
+            // through its substs; those are consumed by const evaluation and so
+            // are structural, regardless of the surrounding layout-only context.
+            ConstValue::Unevaluated(_, substs) => {
+                let prev = mem::replace(&mut self.layout_only, false);
+                for subst in substs {
+                    match subst.unpack() {
+                        UnpackedKind::Const(ct) => self.fold_const(ct),
+                        UnpackedKind::Type(ty) => { self.fold_ty(ty); }
+                        UnpackedKind::Lifetime(_) => {}
+                    }
+                }
+                self.layout_only = prev;
+            }
+            _ => {}
+        }
+    }
+}
 
 impl<'a, 'gcx: 'a + 'tcx, 'tcx: 'a> Visitor<'tcx> for SubstsVisitor<'a, 'gcx, 'tcx> {
     fn visit_mir(&mut self, mir: &Mir<'tcx>) {
@@ -151,38 +267,51 @@ impl<'a, 'gcx: 'a + 'tcx, 'tcx: 'a> Visitor<'tcx> for SubstsVisitor<'a, 'gcx, 't
         self.super_mir(mir);
     }
 
-    fn visit_ty(&mut self, ty: &Ty<'tcx>, _: TyContext) {
+    fn visit_ty(&mut self, ty: &Ty<'tcx>, ty_context: TyContext) {
+        // The layout of every local is all codegen needs from its declared type.
+        let layout_only = match ty_context {
+            TyContext::LocalDecl { .. } => true,
+            _ => self.layout_only,
+        };
+        let prev = mem::replace(&mut self.layout_only, layout_only);
         self.fold_ty(ty);
+        self.layout_only = prev;
     }
 
-    /*
-    fn visit_const(&mut self, constant: &&'tcx ty::Const<'tcx>, _location: Location) {
-        if let ConstVal::Unevaluated(_def_id, substs) = constant.val {
-            for subst in substs {
-                if let UnpackedKind::Type(ty) = subst.unpack() {
-                    ty.fold_with(self);
-                }
-            }
-        }
-    }
-    */
-
     fn visit_rvalue(&mut self, rvalue: &Rvalue<'tcx>, location: Location) {
-        let tcx = self.0;
-        match *rvalue {
-            Rvalue::Cast(_kind, ref op, ty) => {
-                self.fold_ty(op.ty(&self.1.local_decls, tcx));
-                self.fold_ty(ty);
-            }
-            _ => {}
-        }
+        // Rvalues whose result is computed purely from the operand/target
+        // layout: a param reached only through these is `LayoutUsed`. Casts
+        // that reify function or trait-object pointers are *not* in this set —
+        // they reference items/vtables and so are structural; only plain
+        // (`Misc`) casts, `size_of`/`align_of` queries and aggregate
+        // construction (field offsets) qualify.
+        let layout_only = match *rvalue {
+            Rvalue::Cast(CastKind::Misc, ..)
+            | Rvalue::NullaryOp(..)
+            | Rvalue::Aggregate(..) => true,
+            _ => false,
+        };
+        let prev = mem::replace(&mut self.layout_only, layout_only);
         self.super_rvalue(rvalue, location);
+        self.layout_only = prev;
+    }
+
+    fn visit_const(&mut self, constant: &&'tcx ty::Const<'tcx>, _: Location) {
+        // Constants are not reached by the layout-only positions above, so fold
+        // their type structurally to preserve coverage of params that only
+        // appear inside a constant operand.
+        let prev = mem::replace(&mut self.layout_only, false);
+        self.fold_ty(constant.ty);
+        self.layout_only = prev;
+        // A const operand is a value reaching codegen (arithmetic, match
+        // discriminant, ...), so any const param it names is `Used`.
+        self.fold_const(constant);
     }
 }
 
 impl<'a, 'gcx: 'a + 'tcx, 'tcx: 'a> TypeFolder<'gcx, 'tcx> for SubstsVisitor<'a, 'gcx, 'tcx> {
     fn tcx<'b>(&'b self) -> TyCtxt<'b, 'gcx, 'tcx> {
-        self.0
+        self.tcx
     }
     fn fold_ty(&mut self, ty: Ty<'tcx>) -> Ty<'tcx> {
         if !ty.needs_subst() {
@@ -190,7 +319,54 @@ impl<'a, 'gcx: 'a + 'tcx, 'tcx: 'a> TypeFolder<'gcx, 'tcx> for SubstsVisitor<'a,
         }
         match ty.sty {
             ty::Param(param) => {
-                self.2.parameters[ParamIdx(param.idx)] = ParamUsage::Used;
+                let usage = if self.layout_only {
+                    ParamUsage::LayoutUsed
+                } else {
+                    ParamUsage::Used
+                };
+                self.mark(ParamIdx(param.idx), usage);
+            }
+            // Projections, trait objects and opaque types can change trait
+            // selection or the vtable, so any param reaching them is structural
+            // regardless of the surrounding layout-only context.
+            ty::Projection(..) | ty::Dynamic(..) | ty::Opaque(..) => {
+                let prev = mem::replace(&mut self.layout_only, false);
+                let ty = ty.super_fold_with(self);
+                self.layout_only = prev;
+                return ty;
+            }
+            // `super_fold_with` does not descend into array-length consts, so
+            // pick up a const param used as a `[T; N]` length here.
+            ty::Array(_, len) => {
+                self.fold_const(len);
+            }
+            // The closure-kind, signature and upvar components of a closure's
+            // substs drive `FnOnce`/`FnMut` selection and the environment
+            // layout, so they are structural. A phantom param living only in
+            // the parent portion of the substs is not reached here and stays
+            // demotable.
+            ty::Closure(def_id, substs) => {
+                let tcx = self.tcx;
+                let prev = mem::replace(&mut self.layout_only, false);
+                self.fold_ty(substs.closure_kind_ty(def_id, tcx));
+                self.fold_ty(substs.closure_sig_ty(def_id, tcx));
+                for upvar_ty in substs.upvar_tys(def_id, tcx) {
+                    self.fold_ty(upvar_ty);
+                }
+                self.layout_only = prev;
+                return ty;
+            }
+            ty::Generator(def_id, substs, _) => {
+                let tcx = self.tcx;
+                let prev = mem::replace(&mut self.layout_only, false);
+                for upvar_ty in substs.upvar_tys(def_id, tcx) {
+                    self.fold_ty(upvar_ty);
+                }
+                self.fold_ty(substs.witness(def_id, tcx));
+                self.fold_ty(substs.return_ty(def_id, tcx));
+                self.fold_ty(substs.yield_ty(def_id, tcx));
+                self.layout_only = prev;
+                return ty;
             }
             _ => {}
         }
@@ -198,26 +374,60 @@ impl<'a, 'gcx: 'a + 'tcx, 'tcx: 'a> TypeFolder<'gcx, 'tcx> for SubstsVisitor<'a,
     }
 }
 
-fn used_substs_for_instance<'a, 'tcx: 'a>(
-    tcx: TyCtxt<'a ,'tcx, 'tcx>,
-    instance: Instance<'tcx>,
-) -> ParamsUsage {
-    let mir = tcx.instance_mir(instance.def);
-    let generics = tcx.generics_of(instance.def_id());
-    let sig = instance.fn_sig(tcx);
-    let sig = tcx.normalize_erasing_late_bound_regions(ty::ParamEnv::reveal_all(), &sig);
-    let mut substs_visitor = SubstsVisitor(tcx, mir, ParamsUsage::new(instance.substs.len()));
-    //substs_visitor.visit_mir(mir);
-    mir.fold_with(&mut substs_visitor);
-    for ty in sig.inputs().iter() {
-        ty.fold_with(&mut substs_visitor);
+/// A canonical stand-in const of type `ty`, used to collapse instances that
+/// differ only in an unused const param. Its concrete bits are irrelevant —
+/// all that matters is that every instance maps to the *same* value.
+fn dummy_const<'a, 'tcx>(tcx: TyCtxt<'a, 'tcx, 'tcx>, ty: Ty<'tcx>) -> &'tcx ty::Const<'tcx> {
+    let layout_cx = LayoutCx {
+        tcx,
+        param_env: ParamEnv::reveal_all(),
+    };
+    let size = layout_cx.layout_of(ty).map(|l| l.size).unwrap_or(Size::ZERO);
+    tcx.mk_const(ty::Const {
+        val: ConstValue::Scalar(Scalar::Bits { bits: 0, size: size.bytes() as u8 }),
+        ty,
+    })
+}
+
+pub(crate) fn provide(providers: &mut Providers) {
+    *providers = Providers {
+        used_substs,
+        ..*providers
+    };
+}
+
+/// Which of a generic item's substs actually influence its codegen.
+///
+/// The result depends only on the generic body and signature, not on the
+/// concrete substs, so it is keyed on the `DefId` and cached across every
+/// instantiation of the item. The `impl_stable_hash_for!` derivations on
+/// `ParamsUsage` let the result participate in the incremental dep-graph.
+fn used_substs<'a, 'tcx>(tcx: TyCtxt<'a, 'tcx, 'tcx>, def_id: DefId) -> ParamsUsage {
+    let mir = tcx.optimized_mir(def_id);
+    let generics = tcx.generics_of(def_id);
+    let mut substs_visitor = SubstsVisitor::new(tcx, generics.count());
+    // Walk the body through the MIR visitor so that the layout-only positions
+    // (local decls, casts, aggregates, ...) can demote params to `LayoutUsed`
+    // instead of blanket-marking every type `Used`. For closures and
+    // generators this also picks up the environment type (and through it the
+    // upvar tuple and call signature) from the self-argument local decl.
+    substs_visitor.visit_mir(mir);
+    // Closures carry their signature in the substs rather than via `fn_sig`, so
+    // only fold the signature for ordinary functions.
+    if !tcx.is_closure(def_id) {
+        let sig = tcx.fn_sig(def_id);
+        let sig = tcx.normalize_erasing_late_bound_regions(ParamEnv::reveal_all(), &sig);
+        // Anything named in the signature is structurally part of the ABI.
+        for ty in sig.inputs().iter() {
+            ty.fold_with(&mut substs_visitor);
+        }
+        sig.output().fold_with(&mut substs_visitor);
     }
     for param_def in &generics.params {
         if ParamTy::for_def(param_def).is_self() {
             // The self parameter is important for trait selection
-            (substs_visitor.2).parameters[ParamIdx(param_def.index)] = ParamUsage::Used;
+            substs_visitor.usage.parameters[ParamIdx(param_def.index)] = ParamUsage::Used;
         }
     }
-    sig.output().fold_with(&mut substs_visitor);
-    substs_visitor.2
+    substs_visitor.usage
 }